@@ -2,10 +2,89 @@ extern crate libc;
 extern crate poet_sys;
 
 use libc::{c_void, c_int, c_uint};
-use std::ffi::CString;
+use std::cell::RefCell;
+use std::error::Error;
+use std::ffi::{CStr, CString};
+use std::fmt;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
 use std::ptr;
+use std::slice;
 use poet_sys::*;
 
+/// Errors returned by the fallible functions in this crate.
+#[derive(Debug)]
+pub enum PoetError {
+    /// The number of control states didn't match the number of cpu states.
+    StateCountMismatch { control: usize, cpu: usize },
+    /// Loading states from a file failed; carries the nonzero return code from the underlying
+    /// `get_control_states`/`get_cpu_states` C call.
+    LoadFailed(c_int),
+    /// `poet_init` returned a null pointer.
+    InitFailed,
+    /// A path couldn't be converted to a C string, e.g. because it contains an interior NUL byte.
+    InvalidPath,
+}
+
+impl fmt::Display for PoetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PoetError::StateCountMismatch { control, cpu } =>
+                write!(f, "number of control states ({}) doesn't match number of cpu states ({})", control, cpu),
+            PoetError::LoadFailed(code) => write!(f, "failed to load states from file (error code {})", code),
+            PoetError::InitFailed => write!(f, "failed to instantiate POET object"),
+            PoetError::InvalidPath => write!(f, "path contains an interior NUL byte"),
+        }
+    }
+}
+
+impl Error for PoetError {}
+
+/// Paths whose bytes plus the trailing NUL fit in this many bytes (i.e. paths shorter than this)
+/// are converted to a C string on the stack; longer paths fall back to a heap-allocated `CString`.
+const STACK_PATH_BUF_LEN: usize = 384;
+
+/// A NUL-terminated C string built from a `Path`, stored on the stack when it fits.
+///
+/// Avoids a heap allocation for the common case of short config/log file paths while still
+/// supporting arbitrarily long ones.
+#[allow(clippy::large_enum_variant)] // the whole point is to avoid boxing the common case
+enum CPathBuf {
+    Stack([u8; STACK_PATH_BUF_LEN], usize),
+    Heap(CString),
+}
+
+impl CPathBuf {
+    fn new<P: AsRef<Path>>(path: P) -> Result<CPathBuf, PoetError> {
+        let bytes: &[u8] = path.as_ref().as_os_str().as_bytes();
+        // Reserve one byte for the trailing NUL.
+        if bytes.len() < STACK_PATH_BUF_LEN {
+            let mut buf = [0u8; STACK_PATH_BUF_LEN];
+            for (i, &b) in bytes.iter().enumerate() {
+                if b == 0 {
+                    return Err(PoetError::InvalidPath);
+                }
+                buf[i] = b;
+            }
+            buf[bytes.len()] = 0;
+            Ok(CPathBuf::Stack(buf, bytes.len() + 1))
+        } else {
+            CString::new(bytes)
+                .map(CPathBuf::Heap)
+                .map_err(|_| PoetError::InvalidPath)
+        }
+    }
+
+    fn as_c_str(&self) -> &CStr {
+        match *self {
+            CPathBuf::Stack(ref buf, len) => unsafe {
+                CStr::from_bytes_with_nul_unchecked(&buf[..len])
+            },
+            CPathBuf::Heap(ref cstr) => cstr.as_c_str(),
+        }
+    }
+}
+
 extern fn apply_cpu_config_wrapper(states: *mut c_void,
                                    num_states: c_uint,
                                    id: c_uint,
@@ -23,6 +102,80 @@ extern fn get_current_cpu_state_wrapper(states: *const c_void,
     }
 }
 
+/// Reconstruct a borrowed slice of `poet_cpu_state_t` from the raw `states`/`num` pair POET
+/// passes to raw `extern fn` callbacks.
+///
+/// # Safety
+/// `states` must point to `num` valid, initialized, contiguous `poet_cpu_state_t` values that
+/// outlive the returned slice, as POET guarantees when invoking a callback.
+pub unsafe fn cpu_states_from_raw<'a>(states: *const c_void, num: c_uint) -> &'a [poet_cpu_state_t] {
+    slice::from_raw_parts(states as *const poet_cpu_state_t, num as usize)
+}
+
+/// Mutable counterpart of [`cpu_states_from_raw`], for the `apply` callback.
+///
+/// # Safety
+/// Same requirements as [`cpu_states_from_raw`].
+pub unsafe fn cpu_states_from_raw_mut<'a>(states: *mut c_void, num: c_uint) -> &'a mut [poet_cpu_state_t] {
+    slice::from_raw_parts_mut(states as *mut poet_cpu_state_t, num as usize)
+}
+
+/// Safe, user-supplied control logic, used in place of raw `extern fn` callbacks.
+///
+/// POET invokes these methods synchronously from within `POET::apply_control`, so
+/// implementations are free to capture and mutate their own state without needing `unsafe`.
+pub trait ControlCallbacks {
+    /// Apply the control/cpu state identified by `id` (the previously-applied state was
+    /// `last_id`).
+    fn apply(&mut self, states: &mut [poet_cpu_state_t], id: u32, last_id: u32);
+
+    /// Return the id of the currently-applied state, or `None` if it can't be determined.
+    fn current_state(&mut self, states: &[poet_cpu_state_t]) -> Option<u32>;
+}
+
+thread_local! {
+    // A stack rather than a single cell so that reentrant calls to `apply_control` (e.g. from
+    // within a callback) don't clobber an in-progress call's callbacks.
+    static CALLBACK_STACK: RefCell<Vec<*mut dyn ControlCallbacks>> = RefCell::new(Vec::new());
+}
+
+extern fn control_callbacks_apply_trampoline(states: *mut c_void,
+                                             num_states: c_uint,
+                                             id: c_uint,
+                                             last_id: c_uint) {
+    // End the borrow before invoking the callback: a reentrant `apply_control` call from within
+    // `apply` (e.g. on another `POET`) would otherwise hit a live immutable borrow on the push
+    // and panic across the FFI boundary.
+    let top = CALLBACK_STACK.with(|stack| stack.borrow().last().copied());
+    if let Some(callbacks) = top {
+        unsafe {
+            let states = cpu_states_from_raw_mut(states, num_states);
+            (*callbacks).apply(states, id, last_id);
+        }
+    }
+}
+
+extern fn control_callbacks_curr_state_trampoline(states: *const c_void,
+                                                  num_states: c_uint,
+                                                  curr_state_id: *mut c_uint) -> c_int {
+    // See the comment in `control_callbacks_apply_trampoline`: end the borrow before calling
+    // into user code, since a reentrant `apply_control` would otherwise panic on the push.
+    let top = CALLBACK_STACK.with(|stack| stack.borrow().last().copied());
+    match top {
+        Some(callbacks) => {
+            unsafe {
+                let states = cpu_states_from_raw(states, num_states);
+                // An unknown state forces POET to call `apply`, mirroring the sentinel used
+                // by hand-written raw callbacks.
+                let id = (*callbacks).current_state(states).unwrap_or(num_states);
+                *curr_state_id = id;
+            }
+            0
+        }
+        None => 1,
+    }
+}
+
 pub fn default_poet_control_state_t() -> poet_control_state_t {
 	poet_control_state_t {
         id: 0,
@@ -40,9 +193,13 @@ pub fn default_poet_cpu_state_t() -> poet_cpu_state_t {
 }
 
 /// Attempt to load control states from a file.
-pub fn poet_get_control_states(filename: Option<&CString>) -> Result<Vec<poet_control_state_t>, &'static str> {
-    let name_ptr = match filename {
-        Some(f) => f.as_ptr(),
+pub fn poet_get_control_states<P: AsRef<Path>>(filename: Option<P>) -> Result<Vec<poet_control_state_t>, PoetError> {
+    let cpath = match filename {
+        Some(f) => Some(CPathBuf::new(f)?),
+        None => None,
+    };
+    let name_ptr = match cpath {
+        Some(ref c) => c.as_c_str().as_ptr(),
         None => ptr::null(),
     };
     let mut states: *mut poet_control_state_t = ptr::null_mut::<poet_control_state_t>();
@@ -52,7 +209,7 @@ pub fn poet_get_control_states(filename: Option<&CString>) -> Result<Vec<poet_co
                                      &mut states,
                                      &mut nstates);
         if res != 0 {
-            return Err("Failed to load control states");
+            return Err(PoetError::LoadFailed(res));
         }
         // clone so we can free C-allocated memory (so user doesn't have to)
         let mut ret = Vec::with_capacity(nstates as usize);
@@ -64,9 +221,13 @@ pub fn poet_get_control_states(filename: Option<&CString>) -> Result<Vec<poet_co
 }
 
 /// Attempt to load cpu states from a file.
-pub fn poet_get_cpu_states(filename: Option<&CString>) -> Result<Vec<poet_cpu_state_t>, &'static str> {
-    let name_ptr = match filename {
-        Some(f) => f.as_ptr(),
+pub fn poet_get_cpu_states<P: AsRef<Path>>(filename: Option<P>) -> Result<Vec<poet_cpu_state_t>, PoetError> {
+    let cpath = match filename {
+        Some(f) => Some(CPathBuf::new(f)?),
+        None => None,
+    };
+    let name_ptr = match cpath {
+        Some(ref c) => c.as_c_str().as_ptr(),
         None => ptr::null(),
     };
     let mut states: *mut poet_cpu_state_t = ptr::null_mut::<poet_cpu_state_t>();
@@ -76,7 +237,7 @@ pub fn poet_get_cpu_states(filename: Option<&CString>) -> Result<Vec<poet_cpu_st
                                  &mut states,
                                  &mut nstates);
         if res != 0 {
-            return Err("Failed to load cpu states");
+            return Err(PoetError::LoadFailed(res));
         }
         // clone so we can free C-allocated memory (so user doesn't have to)
         let mut ret = Vec::with_capacity(nstates as usize);
@@ -92,21 +253,25 @@ pub struct POET {
     /// The underlying C struct `poet_state`.
     pub poet: *mut poet_state,
     pub control_states: Vec<poet_control_state_t>,
-    pub cpu_states: Vec<poet_cpu_state_t>
+    pub cpu_states: Vec<poet_cpu_state_t>,
+    callbacks: Option<Box<dyn ControlCallbacks>>,
 }
 
 impl POET {
     /// Attempt to initialize POET and allocate resources in the underlying C struct.
-    pub fn new(perf_goal: f64,
+    pub fn new<P: AsRef<Path>>(perf_goal: f64,
                mut control_states: Vec<poet_control_state_t>,
                mut cpu_states: Vec<poet_cpu_state_t>,
                apply_func: Option<poet_apply_func>,
                curr_state_func: Option<poet_curr_state_func>,
                period: u32,
                buffer_depth: u32,
-               log_filename: Option<&CString>) -> Result<POET, &'static str> {
+               log_filename: Option<P>) -> Result<POET, PoetError> {
         if control_states.len() != cpu_states.len() {
-            return Err("Number of control and cpu states don't match");
+            return Err(PoetError::StateCountMismatch {
+                control: control_states.len(),
+                cpu: cpu_states.len(),
+            });
         }
         // the following necessary cast for None seem to be a bug in Rust coercion
         let apply_func: poet_apply_func = match apply_func {
@@ -117,8 +282,12 @@ impl POET {
             Some(p) => p,
             None => get_current_cpu_state_wrapper,
         };
-        let log_ptr = match log_filename {
-            Some(l) => l.as_ptr(),
+        let log_cpath = match log_filename {
+            Some(l) => Some(CPathBuf::new(l)?),
+            None => None,
+        };
+        let log_ptr = match log_cpath {
+            Some(ref c) => c.as_c_str().as_ptr(),
             None => ptr::null(),
         };
         let poet = unsafe {
@@ -129,20 +298,49 @@ impl POET {
                       period, buffer_depth, log_ptr)
         };
         if poet.is_null() {
-            return Err("Failed to instantiate POET object");
+            return Err(PoetError::InitFailed);
         }
         Ok(POET {
         	poet: poet,
         	control_states: control_states,
         	cpu_states: cpu_states,
+        	callbacks: None,
         })
     }
 
+    /// Attempt to initialize POET with safe `ControlCallbacks` instead of raw `extern fn`
+    /// pointers. POET owns `callbacks` and invokes it synchronously from `apply_control`.
+    pub fn with_callbacks<P: AsRef<Path>>(perf_goal: f64,
+               control_states: Vec<poet_control_state_t>,
+               cpu_states: Vec<poet_cpu_state_t>,
+               callbacks: Box<dyn ControlCallbacks>,
+               period: u32,
+               buffer_depth: u32,
+               log_filename: Option<P>) -> Result<POET, PoetError> {
+        let mut poet = POET::new(perf_goal,
+                                 control_states, cpu_states,
+                                 Some(control_callbacks_apply_trampoline),
+                                 Some(control_callbacks_curr_state_trampoline),
+                                 period, buffer_depth, log_filename)?;
+        poet.callbacks = Some(callbacks);
+        Ok(poet)
+    }
+
     /// Call at every iteration - at specified periods this function will (potentially) order
     /// changes to system or application state to try and meet timing constraints.
     pub fn apply_control(&mut self, tag: u64, window_rate: f64, window_power: f64) {
-        unsafe {
-            poet_apply_control(self.poet, tag, window_rate, window_power);
+        match self.callbacks {
+            Some(ref mut callbacks) => {
+                let ptr: *mut dyn ControlCallbacks = &mut **callbacks;
+                CALLBACK_STACK.with(|stack| stack.borrow_mut().push(ptr));
+                unsafe {
+                    poet_apply_control(self.poet, tag, window_rate, window_power);
+                }
+                CALLBACK_STACK.with(|stack| { stack.borrow_mut().pop(); });
+            }
+            None => unsafe {
+                poet_apply_control(self.poet, tag, window_rate, window_power);
+            },
         }
     }
 }
@@ -160,7 +358,7 @@ impl Drop for POET {
 mod test {
     use super::*;
     use libc::{c_void, c_uint};
-    use std::ffi::CString;
+    use std::rc::Rc;
 
     #[test]
     fn test_basic() {
@@ -169,21 +367,39 @@ mod test {
         let mut poet = POET::new(100.0,
                                  control_states, cpu_states,
                                  None, None,
-                                 20u32, 1u32, None).unwrap();
+                                 20u32, 1u32, None::<&str>).unwrap();
         poet.apply_control(0, 1.0, 1.0);
     }
 
     #[test]
     fn test_control_cpu_files_with_log() {
-        let control_states = poet_get_control_states(Some(&CString::new("test/control_config").unwrap())).unwrap();
-        let cpu_states = poet_get_cpu_states(Some(&CString::new("test/cpu_config").unwrap())).unwrap();
+        let control_states = poet_get_control_states(Some("test/control_config")).unwrap();
+        let cpu_states = poet_get_cpu_states(Some("test/cpu_config")).unwrap();
         let mut poet = POET::new(100.0,
                                  control_states, cpu_states,
                                  None, None,
-                                 20u32, 1u32, Some(&CString::new("poet.log").unwrap())).unwrap();
+                                 20u32, 1u32, Some("poet.log")).unwrap();
         poet.apply_control(0, 1.0, 1.0);
     }
 
+    #[test]
+    fn test_c_path_buf_rejects_interior_nul() {
+        match CPathBuf::new("foo\0bar") {
+            Err(PoetError::InvalidPath) => {}
+            other => panic!("expected Err(PoetError::InvalidPath), got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_c_path_buf_heap_fallback_for_long_paths() {
+        // One byte too long for the stack buffer (which also needs room for the trailing NUL),
+        // so this must take the heap `CString` branch.
+        let long_name = "a".repeat(STACK_PATH_BUF_LEN);
+        let cpath = CPathBuf::new(long_name.clone()).unwrap();
+        assert!(matches!(cpath, CPathBuf::Heap(_)));
+        assert_eq!(cpath.as_c_str().to_str().unwrap(), long_name);
+    }
+
     #[test]
     fn test_rust_callbacks() {
         let control_states = vec![default_poet_control_state_t()];
@@ -191,10 +407,100 @@ mod test {
         let mut poet = POET::new(100.0,
                                  control_states, cpu_states,
                                  Some(dummy_apply), Some(dummy_curr_state),
-                                 20u32, 1u32, None).unwrap();
+                                 20u32, 1u32, None::<&str>).unwrap();
+        for i in 0..50 {
+            poet.apply_control(i, 1.0, 1.0);
+        }
+    }
+
+    struct CountingCallbacks {
+        apply_calls: Rc<RefCell<u32>>,
+    }
+
+    impl ControlCallbacks for CountingCallbacks {
+        fn apply(&mut self, _states: &mut [poet_cpu_state_t], _id: u32, _last_id: u32) {
+            *self.apply_calls.borrow_mut() += 1;
+        }
+
+        fn current_state(&mut self, _states: &[poet_cpu_state_t]) -> Option<u32> {
+            // always unknown, so POET calls `apply` every time
+            None
+        }
+    }
+
+    #[test]
+    fn test_control_callbacks() {
+        let control_states = vec![default_poet_control_state_t()];
+        let cpu_states = vec![default_poet_cpu_state_t()];
+        let apply_calls = Rc::new(RefCell::new(0));
+        let mut poet = POET::with_callbacks(100.0,
+                                 control_states, cpu_states,
+                                 Box::new(CountingCallbacks { apply_calls: apply_calls.clone() }),
+                                 20u32, 1u32, None::<&str>).unwrap();
         for i in 0..50 {
             poet.apply_control(i, 1.0, 1.0);
         }
+        assert!(*apply_calls.borrow() > 0);
+    }
+
+    struct ReentrantCallbacks {
+        inner: RefCell<Option<POET>>,
+    }
+
+    impl ControlCallbacks for ReentrantCallbacks {
+        fn apply(&mut self, _states: &mut [poet_cpu_state_t], _id: u32, _last_id: u32) {
+            if let Some(ref mut inner) = *self.inner.borrow_mut() {
+                inner.apply_control(0, 1.0, 1.0);
+            }
+        }
+
+        fn current_state(&mut self, _states: &[poet_cpu_state_t]) -> Option<u32> {
+            None
+        }
+    }
+
+    struct NoOpCallbacks;
+
+    impl ControlCallbacks for NoOpCallbacks {
+        fn apply(&mut self, _states: &mut [poet_cpu_state_t], _id: u32, _last_id: u32) {}
+
+        fn current_state(&mut self, _states: &[poet_cpu_state_t]) -> Option<u32> {
+            None
+        }
+    }
+
+    #[test]
+    fn test_reentrant_apply_control() {
+        let inner_control_states = vec![default_poet_control_state_t()];
+        let inner_cpu_states = vec![default_poet_cpu_state_t()];
+        // `inner` also uses `ControlCallbacks`, so its `apply_control` pushes onto the same
+        // thread-local `CALLBACK_STACK` as `outer` - the scenario that used to panic.
+        let inner = POET::with_callbacks(100.0,
+                              inner_control_states, inner_cpu_states,
+                              Box::new(NoOpCallbacks),
+                              20u32, 1u32, None::<&str>).unwrap();
+
+        let outer_control_states = vec![default_poet_control_state_t()];
+        let outer_cpu_states = vec![default_poet_cpu_state_t()];
+        let mut outer = POET::with_callbacks(100.0,
+                                 outer_control_states, outer_cpu_states,
+                                 Box::new(ReentrantCallbacks { inner: RefCell::new(Some(inner)) }),
+                                 20u32, 1u32, None::<&str>).unwrap();
+        // Calling `apply_control` on `inner` from within the outer callback used to panic on a
+        // live `CALLBACK_STACK` borrow and abort the process across the FFI boundary.
+        outer.apply_control(0, 1.0, 1.0);
+    }
+
+    #[test]
+    fn test_cpu_states_from_raw() {
+        let mut states = vec![default_poet_cpu_state_t(), default_poet_cpu_state_t()];
+        let ptr = states.as_mut_ptr() as *mut c_void;
+        let num = states.len() as c_uint;
+        unsafe {
+            assert_eq!(cpu_states_from_raw(ptr as *const c_void, num).len(), 2);
+            cpu_states_from_raw_mut(ptr, num)[0].id = 42;
+        }
+        assert_eq!(states[0].id, 42);
     }
 
     extern fn dummy_apply(_states: *mut c_void,